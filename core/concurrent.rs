@@ -15,12 +15,14 @@ use super::arc::Arc;
 use super::deque::Deque;
 use super::mem::transmute;
 use super::thread::{Mutex, Cond};
+use super::time;
 
 #[no_freeze]
 struct QueueBox<T> {
     deque: Deque<T>,
     mutex: Mutex,
-    not_empty: Cond
+    not_empty: Cond,
+    closed: bool
 }
 
 /// An unbounded, blocking concurrent queue
@@ -32,31 +34,95 @@ impl<T> Queue<T> {
     /// Return a new `Queue` instance
     pub fn new() -> Queue<T> {
         unsafe {
-            let box = QueueBox { deque: Deque::new(), mutex: Mutex::new(), not_empty: Cond::new() };
+            let box = QueueBox { deque: Deque::new(), mutex: Mutex::new(), not_empty: Cond::new(),
+                                  closed: false };
             Queue { ptr: Arc::new_unchecked(box) }
         }
     }
 
-    /// Pop a value from the front of the queue, blocking until the queue is not empty
-    pub fn pop(&self) -> T {
+    /// Pop a value from the front of the queue, blocking until the queue is not
+    /// empty. Returns `None` once the queue has been `close`d and drained
+    /// instead of blocking forever.
+    pub fn pop(&self) -> Option<T> {
         unsafe {
             let box: &mut QueueBox<T> = transmute(self.ptr.borrow());
             let mut guard = box.mutex.lock_guard();
             while box.deque.len() == 0 {
+                if box.closed {
+                    return None;
+                }
                 box.not_empty.wait_guard(&mut guard)
             }
-            box.deque.pop_front().get()
+            Some(box.deque.pop_front().get())
+        }
+    }
+
+    /// Pop a value from the front of the queue without blocking, returning
+    /// `None` immediately if it is empty
+    pub fn try_pop(&self) -> Option<T> {
+        unsafe {
+            let box: &mut QueueBox<T> = transmute(self.ptr.borrow());
+            box.mutex.lock();
+            let item = if box.deque.len() == 0 {
+                None
+            } else {
+                Some(box.deque.pop_front().get())
+            };
+            box.mutex.unlock();
+            item
+        }
+    }
+
+    /// Pop a value from the front of the queue, blocking until the queue is
+    /// not empty or `ms` milliseconds have elapsed, whichever comes first.
+    /// Returns `None` on timeout, or once the queue has been `close`d and
+    /// drained
+    pub fn pop_timeout(&self, ms: uint) -> Option<T> {
+        unsafe {
+            let box: &mut QueueBox<T> = transmute(self.ptr.borrow());
+            let mut guard = box.mutex.lock_guard();
+            let deadline = time::now_ms() + ms;
+            while box.deque.len() == 0 {
+                if box.closed {
+                    return None;
+                }
+                let now = time::now_ms();
+                if now >= deadline {
+                    return None;
+                }
+                box.not_empty.wait_guard_timeout(&mut guard, deadline - now);
+            }
+            Some(box.deque.pop_front().get())
         }
     }
 
-    /// Push a value to the back of the queue
-    pub fn push(&self, item: T) {
+    /// Push a value to the back of the queue. Returns `false` without
+    /// inserting if the queue has been `close`d
+    pub fn push(&self, item: T) -> bool {
         unsafe {
             let box: &mut QueueBox<T> = transmute(self.ptr.borrow());
             box.mutex.lock();
+            if box.closed {
+                box.mutex.unlock();
+                return false;
+            }
             box.deque.push_back(item);
             box.mutex.unlock();
-            box.not_empty.signal()
+            box.not_empty.signal();
+            true
+        }
+    }
+
+    /// Close the queue, waking any blocked `pop`ers and causing future
+    /// `push`es to be rejected. Already-queued items can still be drained
+    /// with `pop`/`try_pop`
+    pub fn close(&self) {
+        unsafe {
+            let box: &mut QueueBox<T> = transmute(self.ptr.borrow());
+            box.mutex.lock();
+            box.closed = true;
+            box.mutex.unlock();
+            box.not_empty.broadcast()
         }
     }
 }
@@ -74,7 +140,8 @@ struct BoundedQueueBox<T> {
     mutex: Mutex,
     not_empty: Cond,
     not_full: Cond,
-    maximum: uint
+    maximum: uint,
+    closed: bool
 }
 
 /// A bounded, blocking concurrent queue
@@ -87,37 +154,151 @@ impl<T> BoundedQueue<T> {
     pub fn new(maximum: uint) -> BoundedQueue<T> {
         unsafe {
             let box = BoundedQueueBox { deque: Deque::new(), mutex: Mutex::new(), not_empty: Cond::new(),
-                                        not_full: Cond::new(), maximum: maximum };
+                                        not_full: Cond::new(), maximum: maximum, closed: false };
             BoundedQueue { ptr: Arc::new_unchecked(box) }
         }
     }
 
-    /// Pop a value from the front of the queue, blocking until the queue is not empty
-    pub fn pop(&self) -> T {
+    /// Pop a value from the front of the queue, blocking until the queue is not
+    /// empty. Returns `None` once the queue has been `close`d and drained
+    /// instead of blocking forever.
+    pub fn pop(&self) -> Option<T> {
         unsafe {
             let box: &mut BoundedQueueBox<T> = transmute(self.ptr.borrow());
             box.mutex.lock();
             while box.deque.len() == 0 {
+                if box.closed {
+                    box.mutex.unlock();
+                    return None;
+                }
                 box.not_empty.wait(&mut box.mutex)
             }
             let item = box.deque.pop_front().get();
             box.mutex.unlock();
             box.not_full.signal();
+            Some(item)
+        }
+    }
+
+    /// Pop a value from the front of the queue without blocking, returning
+    /// `None` immediately if it is empty
+    pub fn try_pop(&self) -> Option<T> {
+        unsafe {
+            let box: &mut BoundedQueueBox<T> = transmute(self.ptr.borrow());
+            box.mutex.lock();
+            let item = if box.deque.len() == 0 {
+                None
+            } else {
+                Some(box.deque.pop_front().get())
+            };
+            box.mutex.unlock();
+            if item.is_some() {
+                box.not_full.signal();
+            }
             item
         }
     }
 
-    /// Push a value to the back of the queue, blocking until the queue is not full
-    pub fn push(&self, item: T) {
+    /// Pop a value from the front of the queue, blocking until the queue is
+    /// not empty or `ms` milliseconds have elapsed, whichever comes first.
+    /// Returns `None` on timeout, or once the queue has been `close`d and
+    /// drained
+    pub fn pop_timeout(&self, ms: uint) -> Option<T> {
+        unsafe {
+            let box: &mut BoundedQueueBox<T> = transmute(self.ptr.borrow());
+            box.mutex.lock();
+            let deadline = time::now_ms() + ms;
+            while box.deque.len() == 0 {
+                if box.closed {
+                    box.mutex.unlock();
+                    return None;
+                }
+                let now = time::now_ms();
+                if now >= deadline {
+                    box.mutex.unlock();
+                    return None;
+                }
+                box.not_empty.wait_timeout(&mut box.mutex, deadline - now);
+            }
+            let item = box.deque.pop_front().get();
+            box.mutex.unlock();
+            box.not_full.signal();
+            Some(item)
+        }
+    }
+
+    /// Push a value to the back of the queue, blocking until the queue is not
+    /// full. Returns `false` without inserting if the queue has been `close`d
+    pub fn push(&self, item: T) -> bool {
         unsafe {
             let box: &mut BoundedQueueBox<T> = transmute(self.ptr.borrow());
             box.mutex.lock();
-            while box.deque.len() == box.maximum {
+            while !box.closed && box.deque.len() == box.maximum {
                 box.not_full.wait(&mut box.mutex)
             }
+            if box.closed {
+                box.mutex.unlock();
+                return false;
+            }
+            box.deque.push_back(item);
+            box.mutex.unlock();
+            box.not_empty.signal();
+            true
+        }
+    }
+
+    /// Push a value to the back of the queue without blocking, returning the
+    /// item back as `Err` if the queue is full or has been `close`d
+    pub fn try_push(&self, item: T) -> Result<(), T> {
+        unsafe {
+            let box: &mut BoundedQueueBox<T> = transmute(self.ptr.borrow());
+            box.mutex.lock();
+            if box.closed || box.deque.len() == box.maximum {
+                box.mutex.unlock();
+                return Err(item);
+            }
+            box.deque.push_back(item);
+            box.mutex.unlock();
+            box.not_empty.signal();
+            Ok(())
+        }
+    }
+
+    /// Close the queue, waking any blocked `push`ers and `pop`ers and causing
+    /// future `push`es to be rejected. Already-queued items can still be
+    /// drained with `pop`/`try_pop`
+    pub fn close(&self) {
+        unsafe {
+            let box: &mut BoundedQueueBox<T> = transmute(self.ptr.borrow());
+            box.mutex.lock();
+            box.closed = true;
+            box.mutex.unlock();
+            box.not_empty.broadcast();
+            box.not_full.broadcast()
+        }
+    }
+
+    /// Push a value to the back of the queue without blocking. If the queue is
+    /// already at `maximum`, the oldest element is evicted from the front to
+    /// make room and returned as `Some`; otherwise `None` is returned. Also
+    /// returns `None` without inserting if the queue has been `close`d.
+    pub fn force_push(&self, item: T) -> Option<T> {
+        unsafe {
+            let box: &mut BoundedQueueBox<T> = transmute(self.ptr.borrow());
+            box.mutex.lock();
+            if box.closed {
+                box.mutex.unlock();
+                return None;
+            }
+            let evicted = if box.deque.len() > 0 && box.deque.len() == box.maximum {
+                Some(box.deque.pop_front().get())
+            } else {
+                None
+            };
             box.deque.push_back(item);
             box.mutex.unlock();
-            box.not_empty.signal()
+            box.not_empty.signal();
+            evicted
         }
     }
 }
@@ -0,0 +1,154 @@
+// Copyright 2013 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A bounded, lock-free multi-producer multi-consumer queue
+//!
+//! This is an implementation of Dmitry Vyukov's bounded MPMC queue, backed by
+//! a fixed-size array of cells each tagged with a sequence number, described
+//! at http://www.1024cores.net/home/lock-free-algorithms/queues/bounded-mpmc-queue
+
+use super::clone::Clone;
+use super::arc::Arc;
+use super::mem::{transmute, size_of};
+use super::vec;
+use super::atomic::{AtomicUint, Acquire, Release, Relaxed};
+
+struct Cell<T> {
+    sequence: AtomicUint,
+    value: Option<T>
+}
+
+#[no_freeze]
+struct QueueBox<T> {
+    buffer: ~[Cell<T>],
+    mask: uint,
+    enqueue_pos: AtomicUint,
+    dequeue_pos: AtomicUint
+}
+
+/// A fixed-capacity, lock-free multi-producer multi-consumer queue
+pub struct MpmcQueue<T> {
+    priv ptr: Arc<QueueBox<T>>
+}
+
+fn next_power_of_two(n: uint) -> uint {
+    let high_bit = 1 << (size_of::<uint>() * 8 - 1);
+    let mut cap = 1;
+    while cap < n {
+        assert!(cap & high_bit == 0, "MpmcQueue: capacity too large");
+        cap <<= 1;
+    }
+    cap
+}
+
+impl<T> MpmcQueue<T> {
+    /// Return a new `MpmcQueue` instance, holding at most `capacity` elements.
+    /// `capacity` is rounded up to the next power of two.
+    pub fn new(capacity: uint) -> MpmcQueue<T> {
+        unsafe {
+            let capacity = next_power_of_two(capacity);
+            let buffer = vec::from_fn(capacity, |i| {
+                Cell { sequence: AtomicUint::new(i), value: None }
+            });
+            let box = QueueBox { buffer: buffer, mask: capacity - 1,
+                                  enqueue_pos: AtomicUint::new(0), dequeue_pos: AtomicUint::new(0) };
+            MpmcQueue { ptr: Arc::new_unchecked(box) }
+        }
+    }
+
+    /// Push a value onto the queue without blocking, returning the value back
+    /// as `Err` if the queue is full
+    pub fn try_push(&self, value: T) -> Result<(), T> {
+        unsafe {
+            let box: &mut QueueBox<T> = transmute(self.ptr.borrow());
+            let mask = box.mask;
+            let mut pos = box.enqueue_pos.load(Relaxed);
+            loop {
+                let cell = &mut box.buffer[pos & mask];
+                let seq = cell.sequence.load(Acquire);
+                let diff = seq as int - pos as int;
+                if diff == 0 {
+                    if box.enqueue_pos.compare_and_swap(pos, pos + 1, Relaxed) == pos {
+                        cell.value = Some(value);
+                        cell.sequence.store(pos + 1, Release);
+                        return Ok(());
+                    }
+                } else if diff < 0 {
+                    return Err(value);
+                } else {
+                    pos = box.enqueue_pos.load(Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Pop a value from the queue without blocking, returning `None` if it is
+    /// empty
+    pub fn try_pop(&self) -> Option<T> {
+        unsafe {
+            let box: &mut QueueBox<T> = transmute(self.ptr.borrow());
+            let mask = box.mask;
+            let mut pos = box.dequeue_pos.load(Relaxed);
+            loop {
+                let cell = &mut box.buffer[pos & mask];
+                let seq = cell.sequence.load(Acquire);
+                let diff = seq as int - (pos + 1) as int;
+                if diff == 0 {
+                    if box.dequeue_pos.compare_and_swap(pos, pos + 1, Relaxed) == pos {
+                        let value = cell.value.take();
+                        cell.sequence.store(pos + mask + 1, Release);
+                        return Some(value.get());
+                    }
+                } else if diff < 0 {
+                    return None;
+                } else {
+                    pos = box.dequeue_pos.load(Relaxed);
+                }
+            }
+        }
+    }
+}
+
+impl<T> Clone for MpmcQueue<T> {
+    /// Return a shallow copy of the `MpmcQueue`
+    fn clone(&self) -> MpmcQueue<T> {
+        MpmcQueue { ptr: self.ptr.clone() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MpmcQueue, next_power_of_two};
+
+    #[test]
+    fn push_pop_preserves_order() {
+        let q = MpmcQueue::new(4);
+
+        let mut i = 0;
+        while i < 4 {
+            assert!(q.try_push(i).is_ok());
+            i += 1;
+        }
+        assert!(q.try_push(4).is_err());
+
+        let mut i = 0;
+        while i < 4 {
+            assert_eq!(q.try_pop(), Some(i));
+            i += 1;
+        }
+        assert_eq!(q.try_pop(), None);
+    }
+
+    #[test]
+    #[should_fail]
+    fn next_power_of_two_panics_near_uint_max() {
+        next_power_of_two(-1 as uint);
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright 2013 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A lock-free, intrusive multi-producer single-consumer queue
+//!
+//! This is an implementation of Dmitry Vyukov's intrusive MPSC node-based
+//! queue, described at
+//! http://www.1024cores.net/home/lock-free-algorithms/queues/intrusive-mpsc-node-based-queue
+
+use super::clone::Clone;
+use super::ops::Drop;
+use super::arc::Arc;
+use super::mem::transmute;
+use super::atomic::{AtomicPtr, Acquire, Release, AcqRel};
+
+/// The result of a `pop` operation
+pub enum PopResult<T> {
+    /// A value was successfully popped off the front of the queue
+    Data(T),
+    /// The queue is empty
+    Empty,
+    /// The queue is in an inconsistent state: a producer has reserved a slot
+    /// by swapping into `head` but has not yet linked it into the list.
+    /// Consumers should spin and retry rather than treat this as `Empty`.
+    Inconsistent
+}
+
+struct Node<T> {
+    next: AtomicPtr<Node<T>>,
+    value: Option<T>
+}
+
+impl<T> Node<T> {
+    unsafe fn new(value: Option<T>) -> *mut Node<T> {
+        transmute(~Node { next: AtomicPtr::new(0 as *mut Node<T>), value: value })
+    }
+}
+
+#[no_freeze]
+struct QueueBox<T> {
+    head: AtomicPtr<Node<T>>,
+    tail: *mut Node<T>
+}
+
+impl<T> Drop for QueueBox<T> {
+    /// Free the stub node and every node still linked ahead of it, dropping
+    /// any values that were never `pop`ped
+    fn drop(&mut self) {
+        unsafe {
+            let mut cur = self.tail;
+            while !cur.is_null() {
+                let next = (*cur).next.load(Acquire);
+                let _: ~Node<T> = transmute(cur);
+                cur = next;
+            }
+        }
+    }
+}
+
+/// An unbounded, lock-free multi-producer single-consumer queue
+pub struct MpscQueue<T> {
+    priv ptr: Arc<QueueBox<T>>
+}
+
+impl<T> MpscQueue<T> {
+    /// Return a new `MpscQueue` instance
+    pub fn new() -> MpscQueue<T> {
+        unsafe {
+            let stub = Node::new(None);
+            let box = QueueBox { head: AtomicPtr::new(stub), tail: stub };
+            MpscQueue { ptr: Arc::new_unchecked(box) }
+        }
+    }
+
+    /// Push a value onto the back of the queue. Safe to call concurrently
+    /// from any number of producer threads.
+    pub fn push(&self, value: T) {
+        unsafe {
+            let box: &mut QueueBox<T> = transmute(self.ptr.borrow());
+            let node = Node::new(Some(value));
+            let prev = box.head.swap(node, AcqRel);
+            (*prev).next.store(node, Release);
+        }
+    }
+
+    /// Pop a value from the front of the queue. Only safe to call from a
+    /// single consumer thread; see `PopResult` for the possible outcomes.
+    pub fn pop(&self) -> PopResult<T> {
+        unsafe {
+            let box: &mut QueueBox<T> = transmute(self.ptr.borrow());
+            let tail = box.tail;
+            let next = (*tail).next.load(Acquire);
+
+            if !next.is_null() {
+                box.tail = next;
+                let value = (*next).value.take();
+                let _: ~Node<T> = transmute(tail);
+                return Data(value.get());
+            }
+
+            if box.head.load(Acquire) == tail { Empty } else { Inconsistent }
+        }
+    }
+}
+
+impl<T> Clone for MpscQueue<T> {
+    /// Return a shallow copy of the `MpscQueue`
+    fn clone(&self) -> MpscQueue<T> {
+        MpscQueue { ptr: self.ptr.clone() }
+    }
+}
@@ -0,0 +1,303 @@
+// Copyright 2013 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A segmented, unbounded, lock-free multi-producer multi-consumer queue
+//!
+//! `MpscQueue` allocates one `Node` per element, which dominates throughput
+//! for deep queues under heavy churn. `SegQueue` amortizes that allocation by
+//! storing elements in fixed-size blocks linked through an atomic pointer, in
+//! the style of crossbeam's `SegQueue`.
+
+use super::clone::Clone;
+use super::ops::Drop;
+use super::arc::Arc;
+use super::mem::transmute;
+use super::intrinsics;
+use super::atomic::{AtomicUint, AtomicPtr, Acquire, Release, AcqRel};
+
+// Slots per block. One slot's worth of index space is reserved to mark the
+// boundary between blocks, so `BLOCK_CAP` is `LAP - 1`.
+static LAP: uint = 32;
+static BLOCK_CAP: uint = LAP - 1;
+static SHIFT: uint = 1;
+
+// Set in `tail.index` once the queue has been `close`d.
+static MARK_BIT: uint = 1;
+
+// Per-slot state bits.
+static WRITE: uint = 1;
+static READ: uint = 2;
+static DESTROY: uint = 4;
+
+struct Slot<T> {
+    value: Option<T>,
+    state: AtomicUint
+}
+
+struct Block<T> {
+    next: AtomicPtr<Block<T>>,
+    slots: [Slot<T>, ..BLOCK_CAP]
+}
+
+impl<T> Block<T> {
+    unsafe fn new() -> *mut Block<T> {
+        let block: ~Block<T> = intrinsics::init();
+        transmute(block)
+    }
+
+    /// Mark slots `[start, BLOCK_CAP - 1)` as destroyed, and free the block
+    /// once every one of them has been read. The last slot is not checked:
+    /// its reader is the one that calls `destroy(block, 0)`.
+    unsafe fn destroy(this: *mut Block<T>, start: uint) {
+        let mut i = start;
+        while i < BLOCK_CAP - 1 {
+            let slot = &(*this).slots[i];
+            if slot.state.load(Acquire) & READ == 0 &&
+               slot.state.fetch_or(DESTROY, AcqRel) & READ == 0 {
+                // Not read yet; whichever consumer reads it will finish
+                // destroying the block.
+                return;
+            }
+            i += 1;
+        }
+        let _: ~Block<T> = transmute(this);
+    }
+}
+
+struct Position<T> {
+    index: AtomicUint,
+    block: AtomicPtr<Block<T>>
+}
+
+#[no_freeze]
+struct QueueBox<T> {
+    head: Position<T>,
+    tail: Position<T>
+}
+
+impl<T> Drop for QueueBox<T> {
+    /// Free every block still linked between `head` and `tail`, dropping any
+    /// values that were never `pop`ped
+    fn drop(&mut self) {
+        unsafe {
+            let tail_block = self.tail.block.load(Acquire);
+            let tail_offset = (self.tail.index.load(Acquire) >> SHIFT) % LAP;
+
+            let mut block = self.head.block.load(Acquire);
+            let mut offset = (self.head.index.load(Acquire) >> SHIFT) % LAP;
+
+            while !block.is_null() {
+                let last = block == tail_block;
+                let end = if last { tail_offset } else { BLOCK_CAP };
+
+                let mut i = offset;
+                while i < end && i < BLOCK_CAP {
+                    (*block).slots[i].value.take();
+                    i += 1;
+                }
+
+                let next = (*block).next.load(Acquire);
+                let _: ~Block<T> = transmute(block);
+
+                if last {
+                    break;
+                }
+                block = next;
+                offset = 0;
+            }
+        }
+    }
+}
+
+/// An unbounded, lock-free multi-producer multi-consumer queue that stores
+/// its elements in fixed-size blocks rather than one allocation per element
+pub struct SegQueue<T> {
+    priv ptr: Arc<QueueBox<T>>
+}
+
+impl<T> SegQueue<T> {
+    /// Return a new, empty `SegQueue` instance
+    pub fn new() -> SegQueue<T> {
+        unsafe {
+            let block = Block::new();
+            let box = QueueBox {
+                head: Position { index: AtomicUint::new(0), block: AtomicPtr::new(block) },
+                tail: Position { index: AtomicUint::new(0), block: AtomicPtr::new(block) }
+            };
+            SegQueue { ptr: Arc::new_unchecked(box) }
+        }
+    }
+
+    /// Push a value onto the back of the queue. Returns `false` without
+    /// inserting if the queue has been `close`d. Safe to call concurrently
+    /// from any number of producer threads.
+    pub fn push(&self, value: T) -> bool {
+        unsafe {
+            let box: &mut QueueBox<T> = transmute(self.ptr.borrow());
+            let mut tail = box.tail.index.load(Acquire);
+            let mut block = box.tail.block.load(Acquire);
+
+            loop {
+                if tail & MARK_BIT != 0 {
+                    return false;
+                }
+
+                let offset = (tail >> SHIFT) % LAP;
+
+                // The producer ahead of us is still linking in the next
+                // block; spin until it finishes.
+                if offset == BLOCK_CAP {
+                    tail = box.tail.index.load(Acquire);
+                    block = box.tail.block.load(Acquire);
+                    continue;
+                }
+
+                let new_tail = tail + (1 << SHIFT);
+
+                if box.tail.index.compare_and_swap(tail, new_tail, AcqRel) == tail {
+                    // We reserved slot `offset` in `block`. If it was the
+                    // last slot, allocate and link the next block before
+                    // writing the value.
+                    if offset + 1 == BLOCK_CAP {
+                        let next = Block::new();
+                        (*block).next.store(next, Release);
+                        box.tail.block.store(next, Release);
+
+                        // Bump the index past the marker slot without
+                        // clobbering a `MARK_BIT` that a concurrent `close()`
+                        // may have just set on `new_tail`.
+                        let bumped = new_tail + (1 << SHIFT);
+                        loop {
+                            let cur = box.tail.index.load(Acquire);
+                            let mark = cur & MARK_BIT;
+                            if box.tail.index.compare_and_swap(cur, bumped | mark, AcqRel) == cur {
+                                break;
+                            }
+                        }
+                    }
+
+                    let slot = &mut (*block).slots[offset];
+                    slot.value = Some(value);
+                    slot.state.fetch_or(WRITE, Release);
+                    return true;
+                }
+
+                tail = box.tail.index.load(Acquire);
+                block = box.tail.block.load(Acquire);
+            }
+        }
+    }
+
+    /// Pop a value from the front of the queue, returning `None` if it is
+    /// empty. Safe to call concurrently from any number of consumer threads.
+    pub fn pop(&self) -> Option<T> {
+        unsafe {
+            let box: &mut QueueBox<T> = transmute(self.ptr.borrow());
+            let mut head = box.head.index.load(Acquire);
+            let mut block = box.head.block.load(Acquire);
+
+            loop {
+                let offset = (head >> SHIFT) % LAP;
+
+                if offset == BLOCK_CAP {
+                    head = box.head.index.load(Acquire);
+                    block = box.head.block.load(Acquire);
+                    continue;
+                }
+
+                let tail = box.tail.index.load(Acquire);
+                if head >> SHIFT == tail >> SHIFT {
+                    return None;
+                }
+
+                let new_head = head + (1 << SHIFT);
+
+                if box.head.index.compare_and_swap(head, new_head, AcqRel) == head {
+                    if offset + 1 == BLOCK_CAP {
+                        // Wait for the producer that is linking the next
+                        // block in before following it.
+                        let mut next = (*block).next.load(Acquire);
+                        while next.is_null() {
+                            next = (*block).next.load(Acquire);
+                        }
+                        box.head.block.store(next, Release);
+                        box.head.index.store(new_head + (1 << SHIFT), Release);
+                    }
+
+                    let slot = &mut (*block).slots[offset];
+                    while slot.state.load(Acquire) & WRITE == 0 {
+                        // Wait for the producer to finish writing.
+                    }
+                    let value = slot.value.take();
+
+                    if offset + 1 == BLOCK_CAP {
+                        Block::destroy(block, 0);
+                    } else if slot.state.fetch_or(READ, AcqRel) & DESTROY != 0 {
+                        Block::destroy(block, offset + 1);
+                    }
+
+                    return Some(value.get());
+                }
+
+                head = box.head.index.load(Acquire);
+                block = box.head.block.load(Acquire);
+            }
+        }
+    }
+
+    /// Close the queue, causing future `push`es to be rejected. Already
+    /// queued items can still be drained with `pop`.
+    pub fn close(&self) {
+        unsafe {
+            let box: &mut QueueBox<T> = transmute(self.ptr.borrow());
+            loop {
+                let tail = box.tail.index.load(Acquire);
+                if tail & MARK_BIT != 0 {
+                    return;
+                }
+                if box.tail.index.compare_and_swap(tail, tail | MARK_BIT, AcqRel) == tail {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl<T> Clone for SegQueue<T> {
+    /// Return a shallow copy of the `SegQueue`
+    fn clone(&self) -> SegQueue<T> {
+        SegQueue { ptr: self.ptr.clone() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SegQueue;
+    use super::BLOCK_CAP;
+
+    #[test]
+    fn spans_multiple_blocks() {
+        let q = SegQueue::new();
+        let n = BLOCK_CAP * 3 + 5;
+
+        let mut i = 0;
+        while i < n {
+            assert!(q.push(i));
+            i += 1;
+        }
+
+        let mut i = 0;
+        while i < n {
+            assert_eq!(q.pop(), Some(i));
+            i += 1;
+        }
+        assert_eq!(q.pop(), None);
+    }
+}